@@ -9,12 +9,51 @@ use diesel::{
         methods::{ExecuteDsl, LimitDsl, LoadQuery},
         RunQueryDsl,
     },
-    result::Error as DieselError,
+    result::{DatabaseErrorInformation, DatabaseErrorKind, Error as DieselError},
 };
 use std::future::Future;
 use std::sync::Arc;
 use std::sync::MutexGuard;
+use std::time::Duration;
 use tokio::task::spawn_blocking;
+use tokio::time::sleep;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Buffer size of the channel backing [`AsyncRunQueryDsl::load_stream_async`].
+const LOAD_STREAM_CHANNEL_CAPACITY: usize = 100;
+
+/// Configuration for [`AsyncConnection::transaction_async_retry`].
+#[derive(Debug, Clone, Copy)]
+pub struct RetryConfig {
+    /// The maximum number of retries attempted after the first failure,
+    /// before giving up and returning the last error.
+    pub max_retries: u32,
+    /// The delay before the first retry.
+    pub base_delay: Duration,
+    /// The maximum delay between retries, regardless of the attempt count.
+    pub max_delay: Duration,
+}
+
+impl RetryConfig {
+    fn delay_for_attempt(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .checked_mul(1u32.checked_shl(attempt).unwrap_or(u32::MAX))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+}
+
+/// Returns true if `err` is a Postgres serialization failure (SQLSTATE
+/// `40001`) or deadlock (SQLSTATE `40P01`).
+fn is_retryable_diesel_error(err: &DieselError) -> bool {
+    match err {
+        DieselError::DatabaseError(DatabaseErrorKind::SerializationFailure, _) => true,
+        DieselError::DatabaseError(_, info) => {
+            matches!(info.code(), Some("40001") | Some("40P01"))
+        }
+        _ => false,
+    }
+}
 
 /// An async variant of [`diesel::connection::SimpleConnection`].
 #[async_trait]
@@ -25,6 +64,102 @@ where
     async fn batch_execute_async(&self, query: &str) -> Result<(), ConnErr>;
 }
 
+/// A guard returned by [`AsyncConnection::begin_test_transaction_async`].
+///
+/// Pins the connection a test transaction was started on, and rolls that
+/// transaction back when dropped.
+pub struct TestTransaction<Conn, Owned>
+where
+    Conn: 'static + DieselConnection,
+{
+    connection: SingleConnection<Conn>,
+    _owned: Arc<Owned>,
+}
+
+impl<Conn, Owned> TestTransaction<Conn, Owned>
+where
+    Conn: 'static + DieselConnection,
+{
+    /// The pinned connection queries should be issued against.
+    pub fn connection(&self) -> &SingleConnection<Conn> {
+        &self.connection
+    }
+}
+
+impl<Conn, Owned> Drop for TestTransaction<Conn, Owned>
+where
+    Conn: 'static + DieselConnection,
+{
+    fn drop(&mut self) {
+        let conn = self.connection.0.clone();
+        let rollback = move || {
+            if let Ok(mut conn) = conn.lock() {
+                let _ = Conn::TransactionManager::rollback_transaction(&mut conn);
+            }
+        };
+
+        // Don't block whatever thread happens to drop us (e.g. a tokio
+        // worker) on this blocking DB round-trip; offload it like every
+        // other blocking Diesel call in this file. Fall back to running it
+        // inline if we're not on a tokio runtime at all.
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(rollback);
+            }
+            Err(_) => rollback(),
+        }
+    }
+}
+
+/// The error returned by [`AsyncConnection::transaction_async_typed`].
+#[derive(Debug)]
+pub enum TransactionError<E, ConnErr> {
+    /// The transaction could not be started.
+    FailedToBegin(ConnErr),
+    /// The user-supplied closure returned an error.
+    UserError(E),
+    /// The closure succeeded, but committing the transaction failed.
+    FailedToCommit(ConnErr),
+    /// The closure returned an error, and the rollback issued in response
+    /// to that error also failed. Both errors are preserved.
+    FailedToRollback { rollback: ConnErr, original: E },
+}
+
+impl<E, ConnErr> std::fmt::Display for TransactionError<E, ConnErr>
+where
+    E: std::fmt::Display,
+    ConnErr: std::fmt::Display,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransactionError::FailedToBegin(err) => write!(f, "failed to begin transaction: {err}"),
+            TransactionError::UserError(err) => write!(f, "{err}"),
+            TransactionError::FailedToCommit(err) => {
+                write!(f, "failed to commit transaction: {err}")
+            }
+            TransactionError::FailedToRollback { rollback, original } => write!(
+                f,
+                "transaction failed with {original}, and the rollback issued in response also failed: {rollback}"
+            ),
+        }
+    }
+}
+
+impl<E, ConnErr> std::error::Error for TransactionError<E, ConnErr>
+where
+    E: std::error::Error + 'static,
+    ConnErr: std::error::Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TransactionError::FailedToBegin(err) => Some(err),
+            TransactionError::UserError(err) => Some(err),
+            TransactionError::FailedToCommit(err) => Some(err),
+            TransactionError::FailedToRollback { rollback, .. } => Some(rollback),
+        }
+    }
+}
+
 /// An async variant of [`diesel::connection::Connection`].
 #[async_trait]
 pub trait AsyncConnection<Conn, ConnErr>: AsyncSimpleConnection<Conn, ConnErr>
@@ -150,6 +285,122 @@ where
             }
         }
     }
+
+    /// Like [`AsyncConnection::transaction_async`], but reports failures as
+    /// a [`TransactionError`] instead of collapsing them into `E`.
+    async fn transaction_async_typed<R, E, Func, Fut, 'a>(
+        &'a self,
+        f: Func,
+    ) -> Result<R, TransactionError<E, ConnErr>>
+    where
+        R: Send + 'static,
+        E: Send,
+        Fut: Future<Output = Result<R, E>> + Send,
+        Func: FnOnce(SingleConnection<Conn>) -> Fut + Send,
+    {
+        let conn = Arc::new(
+            self.get_owned_connection()
+                .await
+                .map_err(TransactionError::FailedToBegin)?,
+        );
+
+        Self::run_with_shared_connection(conn.clone(), |conn| {
+            Conn::TransactionManager::begin_transaction(conn).map_err(ConnErr::from)
+        })
+        .await
+        .map_err(TransactionError::FailedToBegin)?;
+
+        let async_conn = SingleConnection(Self::as_async_conn(&conn).0.clone());
+        match f(async_conn).await {
+            Ok(value) => {
+                Self::run_with_shared_connection(conn.clone(), |conn| {
+                    Conn::TransactionManager::commit_transaction(conn).map_err(ConnErr::from)
+                })
+                .await
+                .map_err(TransactionError::FailedToCommit)?;
+                Ok(value)
+            }
+            Err(user_error) => {
+                match Self::run_with_shared_connection(conn.clone(), |conn| {
+                    Conn::TransactionManager::rollback_transaction(conn).map_err(ConnErr::from)
+                })
+                .await
+                {
+                    Ok(()) => Err(TransactionError::UserError(user_error)),
+                    Err(rollback) => Err(TransactionError::FailedToRollback {
+                        rollback,
+                        original: user_error,
+                    }),
+                }
+            }
+        }
+    }
+
+    /// Like [`AsyncConnection::transaction_async`], but retries with
+    /// exponential backoff on a serialization failure or deadlock. Unlike
+    /// `transaction_async`, `f` may be called more than once.
+    async fn transaction_async_retry<R, E, Func, Fut, 'a>(
+        &'a self,
+        config: RetryConfig,
+        f: Func,
+    ) -> Result<R, E>
+    where
+        R: Send + 'static,
+        E: From<DieselError> + From<ConnErr> + AsRef<DieselError> + Send,
+        Fut: Future<Output = Result<R, E>> + Send,
+        Func: Fn(SingleConnection<Conn>) -> Fut + Send + Sync,
+    {
+        let mut attempt = 0;
+        loop {
+            match self.transaction_async(|conn| f(conn)).await {
+                Err(err)
+                    if attempt < config.max_retries && is_retryable_diesel_error(err.as_ref()) =>
+                {
+                    sleep(config.delay_for_attempt(attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Async variant of [`diesel::Connection::begin_test_transaction`].
+    async fn begin_test_transaction_async(
+        &self,
+    ) -> Result<TestTransaction<Conn, Self::OwnedConnection>, ConnErr> {
+        let conn = Arc::new(self.get_owned_connection().await?);
+
+        Self::run_with_shared_connection(conn.clone(), |conn| {
+            Conn::TransactionManager::begin_transaction(conn).map_err(ConnErr::from)
+        })
+        .await?;
+
+        Ok(TestTransaction {
+            connection: SingleConnection(Self::as_async_conn(&conn).0.clone()),
+            _owned: conn,
+        })
+    }
+}
+
+/// An async variant of [`diesel::result::OptionalExtension`].
+pub trait OptionalExtension<T, E> {
+    /// Converts a [`DieselError::NotFound`] into `Ok(None)`.
+    fn optional(self) -> Result<Option<T>, E>;
+}
+
+impl<T, E> OptionalExtension<T, E> for Result<T, E>
+where
+    E: AsRef<DieselError>,
+{
+    fn optional(self) -> Result<Option<T>, E> {
+        match self {
+            Ok(value) => Ok(Some(value)),
+            Err(err) => match err.as_ref() {
+                DieselError::NotFound => Ok(None),
+                _ => Err(err),
+            },
+        }
+    }
 }
 
 /// An async variant of [`diesel::query_dsl::RunQueryDsl`].
@@ -182,6 +433,14 @@ where
         U: Send + 'static,
         Self: LimitDsl,
         Limit<Self>: LoadQuery<'static, Conn, U>;
+
+    /// Streams the rows of this query instead of collecting them into a
+    /// `Vec` up front.
+    async fn load_stream_async<U>(self, asc: &AsyncConn) -> ReceiverStream<Result<U, E>>
+    where
+        U: Send + 'static,
+        Conn: diesel::connection::LoadConnection,
+        Self: LoadQuery<'static, Conn, U>;
 }
 
 #[async_trait]
@@ -232,6 +491,46 @@ where
     {
         asc.run(|conn| self.first(conn).map_err(E::from)).await
     }
+
+    async fn load_stream_async<U>(self, asc: &AsyncConn) -> ReceiverStream<Result<U, E>>
+    where
+        U: Send + 'static,
+        Conn: diesel::connection::LoadConnection,
+        Self: LoadQuery<'static, Conn, U>,
+    {
+        let (tx, rx) = tokio::sync::mpsc::channel(LOAD_STREAM_CHANNEL_CAPACITY);
+
+        let conn = match asc.get_owned_connection().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                let _ = tx.send(Err(err)).await;
+                return ReceiverStream::new(rx);
+            }
+        };
+
+        tokio::spawn(async move {
+            spawn_blocking(move || {
+                let mut conn = AsyncConn::as_sync_conn(&conn);
+                match self.load_iter::<U>(&mut *conn) {
+                    Ok(iter) => {
+                        for row in iter {
+                            if tx.blocking_send(row.map_err(E::from)).is_err() {
+                                // The stream was dropped; stop loading rows.
+                                break;
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        let _ = tx.blocking_send(Err(E::from(err)));
+                    }
+                }
+            })
+            .await
+            .unwrap() // Propagate panics
+        });
+
+        ReceiverStream::new(rx)
+    }
 }
 
 #[async_trait]